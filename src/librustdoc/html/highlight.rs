@@ -3,7 +3,10 @@
 //! This module uses libsyntax's lexer to provide token-based highlighting for
 //! the HTML documentation generated by rustdoc.
 //!
-//! Use the `render_with_highlighting` to highlight some rust code.
+//! Use `render_with_highlighting` to highlight some rust code as rustdoc's own
+//! HTML. For other backends (a terminal, a structured token list for editor
+//! integrations, ...), drive the classifier directly with `write_source` and a
+//! custom `Writer` implementation.
 
 use crate::html::escape::Escape;
 
@@ -19,11 +22,17 @@ use syntax::token::{self, Token};
 use syntax_pos::{FileName, Span};
 
 /// Highlights `src`, returning the HTML output.
+///
+/// If `linenos` is true, each source line is individually addressable: it's
+/// wrapped in a `<span id="N">` anchor for deep-linking, and a left-hand
+/// gutter of line numbers (excluded from the highlighted code itself, so it
+/// doesn't get copy-pasted along with it) is emitted alongside.
 pub fn render_with_highlighting(
     src: &str,
     class: Option<&str>,
     extension: Option<&str>,
     tooltip: Option<(&str, &str)>,
+    linenos: bool,
 ) -> String {
     debug!("highlighting: ================\n{}\n==============", src);
     let mut out = Vec::new();
@@ -37,25 +46,23 @@ pub fn render_with_highlighting(
         .unwrap();
     }
 
-    let sess = ParseSess::with_silent_emitter();
-    let fm = sess
-        .source_map()
-        .new_source_file(FileName::Custom(String::from("rustdoc-highlighting")), src.to_owned());
     let highlight_result = {
-        let lexer = lexer::StringReader::new(&sess, fm, None);
-        let mut classifier = Classifier::new(lexer, sess.source_map());
-
         let mut highlighted_source = vec![];
-        if classifier.write_source(&mut highlighted_source).is_err() {
-            Err(())
-        } else {
-            Ok(String::from_utf8_lossy(&highlighted_source).into_owned())
+        match write_source(src, &mut HtmlWriter::new(&mut highlighted_source, linenos), linenos) {
+            Ok(recovered) => {
+                if recovered {
+                    debug!("recovered from a lex error while highlighting");
+                }
+                Ok(String::from_utf8_lossy(&highlighted_source).into_owned())
+            }
+            Err(HighlightError::IoError(_)) => Err(()),
         }
     };
 
     match highlight_result {
         Ok(highlighted_source) => {
-            write_header(class, &mut out).unwrap();
+            let gutter = if linenos { Some(line_numbers(source_line_count(src))) } else { None };
+            write_header(class, gutter.as_deref(), &mut out).unwrap();
             write!(out, "{}", highlighted_source).unwrap();
             if let Some(extension) = extension {
                 write!(out, "{}", extension).unwrap();
@@ -72,6 +79,52 @@ pub fn render_with_highlighting(
     String::from_utf8_lossy(&out[..]).into_owned()
 }
 
+/// Renders a plain `1\n2\n...\nn` gutter for `line_numbers(n)` source lines.
+fn line_numbers(n: usize) -> String {
+    (1..=n).map(|i| i.to_string()).collect::<Vec<_>>().join("\n")
+}
+
+/// Number of lines `Classifier` will open `start_line`/`end_line` pairs for.
+///
+/// This has to agree with `Classifier::string`'s counting, which treats every
+/// `\n` byte (including a trailing one) as starting a new line. `src.lines()`
+/// disagrees: it doesn't count a trailing newline as an extra, empty, final
+/// line. Using it here would leave the gutter one row short of the
+/// highlighted code for every snippet ending in `\n`, which is the common
+/// case for a rustdoc code block.
+fn source_line_count(src: &str) -> usize {
+    src.matches('\n').count() + 1
+}
+
+/// Lexes `src` as Rust source and classifies it, calling into `writer` for each
+/// span of highlighted (or unhighlighted) text in sequence. This is the entry
+/// point for driving the classifier with a custom `Writer`; `render_with_highlighting`
+/// is built on top of it with an HTML `Writer`.
+///
+/// Highlighting is best-effort: a byte the lexer can't classify is rendered
+/// unhighlighted rather than aborting the whole source. The returned `bool` is
+/// `true` if that happened anywhere in `src`, so callers can decide whether to
+/// warn about degraded output.
+///
+/// If `with_lines` is true, `writer` additionally gets `start_line`/`end_line`
+/// calls around each source line, so it can anchor or otherwise mark up
+/// individual lines; a `Class` span that crosses a line break (a multi-line
+/// comment or string, say) is itself split in two so the markup each `Writer`
+/// emits stays well-nested.
+pub fn write_source<W: Writer>(
+    src: &str,
+    writer: &mut W,
+    with_lines: bool,
+) -> Result<bool, HighlightError> {
+    let sess = ParseSess::with_silent_emitter();
+    let fm = sess
+        .source_map()
+        .new_source_file(FileName::Custom(String::from("rustdoc-highlighting")), src.to_owned());
+    let lexer = lexer::StringReader::new(&sess, fm, None);
+    let mut classifier = Classifier::new(lexer, sess.source_map(), with_lines);
+    classifier.write_source(writer)
+}
+
 /// Processes a program (nested in the internal `lexer`), classifying strings of
 /// text by highlighting category (`Class`). Calls out to a `Writer` to write
 /// each span of text in sequence.
@@ -84,11 +137,18 @@ struct Classifier<'a> {
     in_attribute: bool,
     in_macro: bool,
     in_macro_nonterminal: bool,
+    // Set once we've had to recover from a lexer error, so callers can tell
+    // the highlighting is only best-effort.
+    recovered: bool,
+    // Whether to tell `out` about line boundaries via `start_line`/`end_line`.
+    with_lines: bool,
+    // 1-based number of the source line currently being written.
+    line: usize,
 }
 
 /// How a span of text is classified. Mostly corresponds to token kinds.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum Class {
+pub enum Class {
     None,
     Comment,
     DocComment,
@@ -105,6 +165,8 @@ enum Class {
     Bool,
     Ident,
     Lifetime,
+    FnCall,
+    Type,
     PreludeTy,
     PreludeVal,
     QuestionMark,
@@ -115,8 +177,10 @@ enum Class {
 ///
 /// The classifier will call into the `Writer` implementation as it finds spans
 /// of text to highlight. Exactly how that text should be highlighted is up to
-/// the implementation.
-trait Writer {
+/// the implementation, which makes it possible to drive the classifier with a
+/// sink other than rustdoc's own HTML, such as an ANSI terminal renderer or a
+/// structured token list for editor integrations.
+pub trait Writer {
     /// Called when we start processing a span of text that should be highlighted.
     /// The `Class` argument specifies how it should be highlighted.
     fn enter_span(&mut self, _: Class) -> io::Result<()>;
@@ -135,29 +199,73 @@ trait Writer {
     /// The latter can be thought of as a shorthand for the former, which is
     /// more flexible.
     fn string<T: Display>(&mut self, text: T, klass: Class) -> io::Result<()>;
+
+    /// Called when a new source line begins, before any spans on that line.
+    /// `line` is its 1-based line number. Only invoked when line-numbered
+    /// output was requested (see `write_source`'s `with_lines` parameter);
+    /// the default implementation does nothing so `Writer`s that don't care
+    /// about line boundaries are unaffected.
+    fn start_line(&mut self, _line: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called at the end of a source line, mirroring `start_line`.
+    fn end_line(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
-// Implement `Writer` for anthing that can be written to, this just implements
-// the default rustdoc behaviour.
-impl<U: Write> Writer for U {
+/// The default `Writer` implementation, rendering spans as HTML `<span>`s with
+/// rustdoc's own CSS classes. This is what backs `render_with_highlighting`;
+/// other backends (terminal, JSON token streams, ...) implement `Writer`
+/// directly instead of going through HTML.
+struct HtmlWriter<W> {
+    out: W,
+    // Whether to wrap each line in a `<span id="N">` deep-link anchor.
+    linenos: bool,
+}
+
+impl<W: Write> HtmlWriter<W> {
+    fn new(out: W, linenos: bool) -> Self {
+        HtmlWriter { out, linenos }
+    }
+}
+
+impl<W: Write> Writer for HtmlWriter<W> {
     fn string<T: Display>(&mut self, text: T, klass: Class) -> io::Result<()> {
         match klass {
-            Class::None => write!(self, "{}", text),
-            klass => write!(self, "<span class=\"{}\">{}</span>", klass.rustdoc_class(), text),
+            Class::None => write!(self.out, "{}", text),
+            klass => write!(self.out, "<span class=\"{}\">{}</span>", klass.rustdoc_class(), text),
         }
     }
 
     fn enter_span(&mut self, klass: Class) -> io::Result<()> {
-        write!(self, "<span class=\"{}\">", klass.rustdoc_class())
+        write!(self.out, "<span class=\"{}\">", klass.rustdoc_class())
     }
 
     fn exit_span(&mut self) -> io::Result<()> {
-        write!(self, "</span>")
+        write!(self.out, "</span>")
+    }
+
+    fn start_line(&mut self, line: usize) -> io::Result<()> {
+        if self.linenos {
+            write!(self.out, "<span id=\"{}\">", line)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn end_line(&mut self) -> io::Result<()> {
+        if self.linenos {
+            write!(self.out, "</span>")
+        } else {
+            Ok(())
+        }
     }
 }
 
-enum HighlightError {
-    LexError,
+#[derive(Debug)]
+pub enum HighlightError {
     IoError(io::Error),
 }
 
@@ -168,7 +276,11 @@ impl From<io::Error> for HighlightError {
 }
 
 impl<'a> Classifier<'a> {
-    fn new(lexer: lexer::StringReader<'a>, source_map: &'a SourceMap) -> Classifier<'a> {
+    fn new(
+        lexer: lexer::StringReader<'a>,
+        source_map: &'a SourceMap,
+        with_lines: bool,
+    ) -> Classifier<'a> {
         Classifier {
             lexer,
             peek_token: None,
@@ -176,17 +288,23 @@ impl<'a> Classifier<'a> {
             in_attribute: false,
             in_macro: false,
             in_macro_nonterminal: false,
+            recovered: false,
+            with_lines,
+            line: 1,
         }
     }
 
-    /// Gets the next token out of the lexer.
+    /// Gets the next token out of the lexer. An unlexable byte is not treated
+    /// as fatal: it's surfaced as `token::Unknown` like any other token (and
+    /// `write_token` renders it unhighlighted), we just remember that we had
+    /// to recover so callers can tell the highlighting is best-effort.
     fn try_next_token(&mut self) -> Result<Token, HighlightError> {
         if let Some(token) = self.peek_token.take() {
             return Ok(token);
         }
         let token = self.lexer.next_token();
         if let token::Unknown(..) = &token.kind {
-            return Err(HighlightError::LexError);
+            self.recovered = true;
         }
         Ok(token)
     }
@@ -195,7 +313,7 @@ impl<'a> Classifier<'a> {
         if self.peek_token.is_none() {
             let token = self.lexer.next_token();
             if let token::Unknown(..) = &token.kind {
-                return Err(HighlightError::LexError);
+                self.recovered = true;
             }
             self.peek_token = Some(token);
         }
@@ -208,8 +326,12 @@ impl<'a> Classifier<'a> {
     /// possibly giving it an HTML span with a class specifying what flavor of token
     /// is used. All source code emission is done as slices from the source map,
     /// not from the tokens themselves, in order to stay true to the original
-    /// source.
-    fn write_source<W: Writer>(&mut self, out: &mut W) -> Result<(), HighlightError> {
+    /// source. Returns whether a lexer error was recovered from along the way.
+    fn write_source<W: Writer>(&mut self, out: &mut W) -> Result<bool, HighlightError> {
+        if self.with_lines {
+            out.start_line(self.line)?;
+        }
+
         loop {
             let next = self.try_next_token()?;
             if next == token::Eof {
@@ -219,6 +341,40 @@ impl<'a> Classifier<'a> {
             self.write_token(out, next)?;
         }
 
+        if self.with_lines {
+            out.end_line()?;
+        }
+
+        Ok(self.recovered)
+    }
+
+    /// Writes `text` as `klass`, the way `write_token` does for the bulk of a
+    /// token's source text. If line numbering is on and `text` spans more than
+    /// one source line (a block comment or multi-line string, say), it's split
+    /// on `\n` so each line gets its own `Class` span and its own
+    /// `start_line`/`end_line` bracketing, keeping the `Writer`'s markup
+    /// well-nested per line.
+    fn string<W: Writer>(&mut self, out: &mut W, text: &str, klass: Class) -> io::Result<()> {
+        if !self.with_lines || !text.contains('\n') {
+            return out.string(Escape(text), klass);
+        }
+
+        let mut lines = text.split('\n');
+        out.string(Escape(lines.next().unwrap()), klass)?;
+        for line in lines {
+            // An open attribute span is closed before the line ends and
+            // reopened on the next line, so it doesn't straddle the line markup.
+            if self.in_attribute {
+                out.exit_span()?;
+            }
+            out.end_line()?;
+            self.line += 1;
+            out.start_line(self.line)?;
+            if self.in_attribute {
+                out.enter_span(Class::Attribute)?;
+            }
+            out.string(Escape(line), klass)?;
+        }
         Ok(())
     }
 
@@ -226,7 +382,7 @@ impl<'a> Classifier<'a> {
     fn write_token<W: Writer>(&mut self, out: &mut W, token: Token) -> Result<(), HighlightError> {
         let klass = match token.kind {
             token::Shebang(s) => {
-                out.string(Escape(&s.as_str()), Class::None)?;
+                self.string(out, &s.as_str(), Class::None)?;
                 return Ok(());
             }
 
@@ -374,6 +530,21 @@ impl<'a> Classifier<'a> {
                     } else if self.peek()? == &token::Not {
                         self.in_macro = true;
                         Class::Macro
+                    } else if self.peek()? == &token::ModSep
+                        || name.as_str().starts_with(|c: char| c.is_uppercase())
+                    {
+                        // `Foo`, `Foo::bar`: a type, by convention or by what
+                        // follows it. Deliberately not gated on a following
+                        // `token::Lt` alone: `x < limit` would otherwise
+                        // misclassify `x` as a type just because it's on the
+                        // left of a comparison. Checked before `FnCall` on
+                        // purpose: a tuple-struct/enum-variant constructor like
+                        // `Foo(1, 2)` is still a `Type`, not a plain function
+                        // call, even though it's followed by `(`.
+                        Class::Type
+                    } else if self.peek()? == &token::OpenDelim(token::Paren) {
+                        // `foo(...)`: a function or method call.
+                        Class::FnCall
                     } else {
                         Class::Ident
                     }
@@ -391,7 +562,8 @@ impl<'a> Classifier<'a> {
 
         // Anything that didn't return above is the simple case where we the
         // class just spans a single token, so we can use the `string` method.
-        out.string(Escape(&self.snip(token.span)), klass)?;
+        let snip = self.snip(token.span);
+        self.string(out, &snip, klass)?;
 
         Ok(())
     }
@@ -421,6 +593,11 @@ impl Class {
             Class::Bool => "bool-val",
             Class::Ident => "ident",
             Class::Lifetime => "lifetime",
+            // No theme in this tree knows `.fn-call`/`.type` yet, so these
+            // render unstyled until CSS is added to alias them to `.ident`;
+            // that follow-up isn't done here.
+            Class::FnCall => "fn-call",
+            Class::Type => "type",
             Class::PreludeTy => "prelude-ty",
             Class::PreludeVal => "prelude-val",
             Class::QuestionMark => "question-mark",
@@ -428,10 +605,103 @@ impl Class {
     }
 }
 
-fn write_header(class: Option<&str>, out: &mut dyn Write) -> io::Result<()> {
-    write!(out, "<div class=\"example-wrap\"><pre class=\"rust {}\">\n", class.unwrap_or(""))
+// `gutter` is the rendered `line_numbers` output, if any; it's emitted as a
+// sibling `<pre>` so the line numbers never end up in a copy-paste of the code.
+fn write_header(class: Option<&str>, gutter: Option<&str>, out: &mut dyn Write) -> io::Result<()> {
+    write!(out, "<div class=\"example-wrap\">")?;
+    if let Some(gutter) = gutter {
+        write!(out, "<pre class=\"line-numbers\">{}</pre>", gutter)?;
+    }
+    write!(out, "<pre class=\"rust {}\">\n", class.unwrap_or(""))
 }
 
 fn write_footer(out: &mut dyn Write) -> io::Result<()> {
     write!(out, "</pre></div>\n")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `Writer` that just records the `(Class, text)` pairs it's given,
+    /// so tests can assert on classification without parsing HTML back out.
+    struct RecordingWriter {
+        spans: Vec<(Class, String)>,
+    }
+
+    impl Writer for RecordingWriter {
+        fn enter_span(&mut self, _: Class) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn exit_span(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn string<T: Display>(&mut self, text: T, klass: Class) -> io::Result<()> {
+            self.spans.push((klass, text.to_string()));
+            Ok(())
+        }
+    }
+
+    fn classify(src: &str) -> Vec<(Class, String)> {
+        let mut writer = RecordingWriter { spans: Vec::new() };
+        write_source(src, &mut writer, false).unwrap();
+        writer.spans
+    }
+
+    fn class_of(src: &str, text: &str) -> Option<Class> {
+        classify(src).into_iter().find(|(_, s)| s == text).map(|(klass, _)| klass)
+    }
+
+    #[test]
+    fn fn_call_is_classified_separately_from_ident() {
+        assert_eq!(class_of("foo(1, 2)", "foo"), Some(Class::FnCall));
+    }
+
+    #[test]
+    fn uppercase_constructor_call_is_a_type_not_a_fn_call() {
+        assert_eq!(class_of("Foo(1, 2)", "Foo"), Some(Class::Type));
+    }
+
+    #[test]
+    fn comparison_operand_is_not_misclassified_as_a_type() {
+        // Regression test: a bare `token::Lt` lookahead used to make `x` look
+        // like the start of `x<T>`, mislabelling every `<` comparison.
+        assert_eq!(class_of("x < limit", "x"), Some(Class::Ident));
+        assert_eq!(class_of("a < b && c > d", "a"), Some(Class::Ident));
+    }
+
+    #[test]
+    fn module_path_segment_is_still_a_type() {
+        assert_eq!(class_of("Foo::bar()", "Foo"), Some(Class::Type));
+    }
+
+    #[test]
+    fn recovers_from_an_unlexable_byte_instead_of_aborting() {
+        let mut writer = RecordingWriter { spans: Vec::new() };
+        let recovered = write_source("let x = 1; \u{1} let y = 2;", &mut writer, false).unwrap();
+        assert!(recovered, "an unlexable byte should be reported as recovered-from");
+        // Highlighting should continue past the bad byte, not just stop there.
+        assert!(writer.spans.iter().any(|(_, text)| text == "y"));
+    }
+
+    #[test]
+    fn source_line_count_counts_a_trailing_newline_as_its_own_line() {
+        // This has to agree with how `Classifier::string` opens lines, or the
+        // line-number gutter falls out of sync with the highlighted code.
+        assert_eq!(source_line_count("a\nb"), 2);
+        assert_eq!(source_line_count("a\nb\n"), 3);
+        assert_eq!(source_line_count(""), 1);
+    }
+
+    #[test]
+    fn render_with_highlighting_puts_a_gutter_line_per_anchor() {
+        let html = render_with_highlighting("fn a() {}\nfn b() {}\n", None, None, None, true);
+        let gutter_lines = source_line_count("fn a() {}\nfn b() {}\n");
+        for line in 1..=gutter_lines {
+            let anchor = format!("id=\"{}\"", line);
+            assert!(html.contains(&anchor), "missing anchor {} in {}", anchor, html);
+        }
+    }
+}